@@ -1,15 +1,23 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use arc_swap::ArcSwapOption;
+use async_compression::tokio::{bufread::ZstdDecoder, write::ZstdEncoder};
+use async_compression::Level;
 use async_trait::async_trait;
+use blake2::{Blake2b512, Digest};
 use futures::future::*;
 use futures::select;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use tokio::fs;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::sync::{watch, Mutex, Notify};
+use zstd::stream::{decode_all, encode_all};
 
 use garage_util::data::*;
 use garage_util::error::*;
@@ -35,6 +43,36 @@ const BLOCK_RW_TIMEOUT: Duration = Duration::from_secs(42);
 const BLOCK_GC_TIMEOUT: Duration = Duration::from_secs(60);
 const NEED_BLOCK_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 const RESYNC_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum power of two applied to `RESYNC_RETRY_TIMEOUT` when computing the exponential
+/// backoff for a block that keeps failing to resync, so the retry delay stops growing after a
+/// while instead of increasing forever.
+const RESYNC_RETRY_BACKOFF_CAP: u32 = 8;
+
+/// Extension appended to the hash-named file of a block that is stored compressed on disk.
+/// Blocks written before compression was enabled are kept without this extension, so both
+/// forms must be recognized when looking up a block.
+const BLOCK_COMPRESSED_EXT: &str = "zst";
+
+/// Size of a single chunk when streaming a block to/from another node. This bounds the amount
+/// of block data that is ever held in memory at once on both ends of the transfer, unlike
+/// `PutBlockMessage`/`read_block` which move the whole block as one buffer.
+const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How long a streamed `PutBlock` can go without receiving a new chunk before it is considered
+/// abandoned (sender crashed, disconnected, or gave up) and reaped by
+/// `cleanup_stale_stream_writes`, freeing its tmp file and its entry in `stream_writes`.
+const STREAM_WRITE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a streamed `GetBlock` can go without a chunk being requested before it is
+/// considered abandoned (requester disconnected or gave up) and reaped by
+/// `cleanup_stale_stream_reads`, freeing its entry in `stream_reads`.
+const STREAM_READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often the stream cleanup worker checks for abandoned streamed reads/writes. Runs on
+/// its own timer rather than piggy-backing on resync queue activity, since the resync queue
+/// can sit idle (or sleep for a long scheduled retry) for far longer than
+/// `STREAM_WRITE_TIMEOUT`/`STREAM_READ_TIMEOUT` while a stream is quietly leaking.
+const STREAM_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
 
 /// RPC messages used to share blocks of data between nodes
 #[derive(Debug, Serialize, Deserialize)]
@@ -49,6 +87,52 @@ pub enum BlockRpc {
 	NeedBlockQuery(Hash),
 	/// Response : whether the node do require that block
 	NeedBlockReply(bool),
+	/// Ask for one chunk of a block, by hash and starting offset, as part of a streamed
+	/// `GetBlock` that avoids buffering the whole block in memory
+	GetBlockStreamChunk(GetBlockStreamChunkRequest),
+	/// One chunk of a block being streamed: either the response to `GetBlockStreamChunk`, or
+	/// pushed unsolicited as part of a streamed `PutBlock`
+	PutBlockStreamChunk(BlockStreamChunk),
+	/// Ask another node whether it actually has this block stored on disk, regardless of
+	/// whether it still needs it. Used after an offload to confirm replicas really hold the
+	/// data before deleting the only other copy: unlike `NeedBlockQuery`, a `false` reply here
+	/// can't be confused with "no longer needs it" (e.g. a concurrent delete on that node).
+	///
+	/// Appended at the end of the enum, like the streamed variants above, so as not to shift
+	/// the wire discriminants of existing variants during a rolling upgrade.
+	HaveBlockQuery(Hash),
+	/// Response: whether the node has this block stored on disk
+	HaveBlockReply(bool),
+}
+
+/// Request for one chunk of a block being streamed, identified by its starting offset in the
+/// (uncompressed) block
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetBlockStreamChunkRequest {
+	/// Hash of the block being streamed
+	pub hash: Hash,
+	/// Offset, in the uncompressed block, at which this chunk starts
+	pub offset: u64,
+	/// Random id chosen once by the requester for this particular streamed transfer, so the
+	/// serving node can tell apart two concurrent streams of the same block from the same
+	/// requester (e.g. two objects referencing the same block, downloaded at the same time)
+	/// when keeping the running integrity hash in `BlockManager::stream_reads`
+	pub request_id: u64,
+}
+
+/// One chunk of a block being streamed to/from another node. `data` is always the plaintext
+/// (uncompressed) content of the block for this chunk.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlockStreamChunk {
+	/// Hash of the block being streamed
+	pub hash: Hash,
+	/// Offset, in the uncompressed block, at which this chunk starts
+	pub offset: u64,
+	/// Content of this chunk
+	#[serde(with = "serde_bytes")]
+	pub data: Vec<u8>,
+	/// Whether this is the last chunk of the block
+	pub is_last: bool,
 }
 
 /// Structure used to send a block
@@ -66,19 +150,61 @@ impl Rpc for BlockRpc {
 	type Response = Result<BlockRpc, Error>;
 }
 
+/// One of possibly several directories in which blocks are stored, e.g. a mounted disk. Several
+/// data directories can be configured so that a node's storage can grow by adding disks instead
+/// of having to reformat onto one larger volume.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+	/// Root path of this data directory
+	pub path: PathBuf,
+	/// Relative capacity of this data directory (e.g. the size in bytes of its underlying
+	/// disk), used to weight how many blocks are placed here compared to the other
+	/// configured data directories
+	pub capacity: u64,
+}
+
 /// The block manager, handling block exchange between nodes, and block storage on local node
 pub struct BlockManager {
 	/// Replication strategy, allowing to find on which node blocks should be located
 	pub replication: TableShardedReplication,
-	/// Directory in which block are stored
-	pub data_dir: PathBuf,
+	/// Directories in which blocks are stored. New blocks are placed in one of these,
+	/// weighted by each directory's configured capacity; existing blocks are looked up by
+	/// probing all of them, since they may have been written before a directory was added.
+	pub data_dirs: Vec<DataDir>,
+	/// Zstd compression level to apply to blocks written to disk, if any.
+	/// `None` means blocks are stored uncompressed.
+	pub compression_level: Option<i32>,
 
 	mutation_lock: Mutex<BlockManagerLocked>,
 
+	/// In-progress streamed block writes, keyed by the sending node and block hash, holding
+	/// the data received so far until the final chunk is validated and committed
+	stream_writes: Mutex<HashMap<(NodeID, Hash), StreamWriteState>>,
+	/// Counter used to give every in-progress streamed write its own tmp file name, so that
+	/// two different senders streaming the same block hash at the same time never share a
+	/// path (see `handle_put_block_stream_chunk`)
+	next_stream_write_id: AtomicU64,
+
+	/// Running hash of the chunks already served for an in-progress streamed `GetBlock`,
+	/// keyed by the requesting node, block hash and the requester's per-transfer
+	/// `request_id` (so two concurrent streams of the same block from the same node don't
+	/// share a hasher), so that a corrupted on-disk block is caught and quarantined the same
+	/// way `read_block` does instead of being streamed out unchecked
+	stream_reads: Mutex<HashMap<(NodeID, Hash, u64), StreamReadState>>,
+
 	rc: sled::Tree,
 
 	resync_queue: sled::Tree,
 	resync_notify: Notify,
+	/// Tracks, for each block hash that has failed to resync at least once, its error count,
+	/// last error message, and next scheduled attempt -- so repeated failures can be backed
+	/// off and persistently unfetchable blocks can be surfaced to operators.
+	resync_errors: sled::Tree,
+
+	/// Counts how many times an offload was deferred because, after sending a block to the
+	/// nodes that needed it, fewer than the replication factor could be confirmed to actually
+	/// hold it -- a sign of replication trouble worth surfacing to operators.
+	offloads_deferred: AtomicU64,
 
 	system: Arc<System>,
 	endpoint: Arc<Endpoint<BlockRpc, Self>>,
@@ -93,10 +219,20 @@ struct BlockManagerLocked();
 impl BlockManager {
 	pub fn new(
 		db: &sled::Db,
-		data_dir: PathBuf,
+		data_dirs: Vec<DataDir>,
+		compression_level: Option<i32>,
 		replication: TableShardedReplication,
 		system: Arc<System>,
 	) -> Arc<Self> {
+		assert!(
+			!data_dirs.is_empty(),
+			"BlockManager needs at least one data directory"
+		);
+		assert!(
+			data_dirs.iter().map(|d| d.capacity).sum::<u64>() > 0,
+			"BlockManager needs at least one data directory with capacity > 0"
+		);
+
 		let rc = db
 			.open_tree("block_local_rc")
 			.expect("Unable to open block_local_rc tree");
@@ -105,6 +241,10 @@ impl BlockManager {
 			.open_tree("block_local_resync_queue")
 			.expect("Unable to open block_local_resync_queue tree");
 
+		let resync_errors = db
+			.open_tree("block_local_resync_errors")
+			.expect("Unable to open block_local_resync_errors tree");
+
 		let endpoint = system
 			.netapp
 			.endpoint("garage_model/block.rs/Rpc".to_string());
@@ -113,11 +253,17 @@ impl BlockManager {
 
 		let block_manager = Arc::new(Self {
 			replication,
-			data_dir,
+			data_dirs,
+			compression_level,
 			mutation_lock: Mutex::new(manager_locked),
+			stream_writes: Mutex::new(HashMap::new()),
+			next_stream_write_id: AtomicU64::new(0),
+			stream_reads: Mutex::new(HashMap::new()),
 			rc,
 			resync_queue,
 			resync_notify: Notify::new(),
+			resync_errors,
+			offloads_deferred: AtomicU64::new(0),
 			system,
 			endpoint,
 			garage: ArcSwapOption::from(None),
@@ -174,6 +320,104 @@ impl BlockManager {
 		Ok(())
 	}
 
+	/// Stream a block to the nodes that should store it, reading it from `reader` in
+	/// bounded-size chunks instead of buffering the whole block in memory as `rpc_put_block`
+	/// does.
+	pub async fn rpc_put_block_stream(
+		&self,
+		hash: Hash,
+		mut reader: impl AsyncRead + Unpin + Send,
+	) -> Result<(), Error> {
+		let who = self.replication.write_nodes(&hash);
+		let mut offset = 0u64;
+
+		loop {
+			let mut buf = vec![0u8; STREAM_CHUNK_SIZE];
+			let filled = read_up_to(&mut reader, &mut buf).await?;
+			buf.truncate(filled);
+			let is_last = filled < STREAM_CHUNK_SIZE;
+
+			self.system
+				.rpc
+				.try_call_many(
+					&self.endpoint,
+					&who[..],
+					BlockRpc::PutBlockStreamChunk(BlockStreamChunk {
+						hash,
+						offset,
+						data: buf,
+						is_last,
+					}),
+					RequestStrategy::with_priority(PRIO_NORMAL)
+						.with_quorum(self.replication.write_quorum())
+						.with_timeout(BLOCK_RW_TIMEOUT),
+				)
+				.await?;
+
+			offset += filled as u64;
+			if is_last {
+				break;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Stream a block from whichever node has it into `writer`, in bounded-size chunks instead
+	/// of buffering the whole block in memory as `rpc_get_block` does.
+	pub async fn rpc_get_block_stream(
+		&self,
+		hash: &Hash,
+		mut writer: impl AsyncWrite + Unpin + Send,
+	) -> Result<(), Error> {
+		let who = self.replication.read_nodes(hash);
+		let mut offset = 0u64;
+		// Chosen once for the whole transfer so a serving node can tell this stream apart
+		// from a concurrent one for the same block from us (see `GetBlockStreamChunkRequest`).
+		let request_id: u64 = rand::thread_rng().gen();
+
+		loop {
+			let resps = self
+				.system
+				.rpc
+				.try_call_many(
+					&self.endpoint,
+					&who[..],
+					BlockRpc::GetBlockStreamChunk(GetBlockStreamChunkRequest {
+						hash: *hash,
+						offset,
+						request_id,
+					}),
+					RequestStrategy::with_priority(PRIO_NORMAL)
+						.with_quorum(1)
+						.with_timeout(BLOCK_RW_TIMEOUT)
+						.interrupt_after_quorum(true),
+				)
+				.await?;
+
+			let chunk = resps.into_iter().find_map(|r| match r {
+				BlockRpc::PutBlockStreamChunk(c) => Some(c),
+				_ => None,
+			});
+			let chunk = chunk.ok_or_else(|| {
+				Error::Message(format!(
+					"Unable to read block {:?}: no valid chunk returned",
+					hash
+				))
+			})?;
+
+			writer.write_all(&chunk.data).await?;
+			offset += chunk.data.len() as u64;
+
+			if chunk.is_last {
+				break;
+			}
+		}
+
+		writer.flush().await?;
+		Ok(())
+	}
+
 	/// Launch the repair procedure on the data store
 	///
 	/// This will list all blocks locally present, as well as those
@@ -211,7 +455,8 @@ impl BlockManager {
 	}
 
 	/// Verify integrity of each block on disk. Use `speed_limit` to limit the load generated by
-	/// this function.
+	/// this function. If compression is enabled, legacy uncompressed blocks are also rewritten
+	/// to their compressed form as they are encountered.
 	pub async fn scrub_data_store(
 		&self,
 		must_exit: &watch::Receiver<bool>,
@@ -222,6 +467,9 @@ impl BlockManager {
 			tranquilizer,
 			move |mut tranquilizer, hash| async move {
 				let _ = self.read_block(&hash).await;
+				if let Err(e) = self.migrate_block_to_compressed(&hash).await {
+					warn!("Could not migrate block {:?} to compressed form: {}", hash, e);
+				}
 				tranquilizer.tranquilize(tranquility).await;
 				Ok(tranquilizer)
 			},
@@ -235,11 +483,43 @@ impl BlockManager {
 		self.resync_queue.len()
 	}
 
+	/// Get number of blocks that have failed to resync at least once and are being tracked
+	/// with a retry backoff
+	pub fn resync_errors_len(&self) -> usize {
+		self.resync_errors.len()
+	}
+
+	/// List blocks that have failed to resync more than `threshold` times, so the admin layer
+	/// can report them as blocks that cannot be resynced
+	pub fn resync_errors_exceeding(&self, threshold: u64) -> Result<Vec<ResyncErrorDetail>, Error> {
+		let mut ret = vec![];
+		for entry in self.resync_errors.iter() {
+			let (k, v) = entry?;
+			let info = rmp_serde::decode::from_read_ref::<_, ResyncErrorInfo>(v.as_ref())?;
+			if info.error_count > threshold {
+				ret.push(ResyncErrorDetail {
+					hash: Hash::try_from(&k[..]).unwrap(),
+					error_count: info.error_count,
+					last_error: info.last_error,
+					next_try: info.next_try,
+				});
+			}
+		}
+		Ok(ret)
+	}
+
 	/// Get number of items in the refcount table
 	pub fn rc_len(&self) -> usize {
 		self.rc.len()
 	}
 
+	/// Get the number of times an offload was deferred because, after sending a block to the
+	/// nodes that needed it, fewer than the replication factor could be confirmed to actually
+	/// hold it
+	pub fn offloads_deferred_len(&self) -> u64 {
+		self.offloads_deferred.load(Ordering::Relaxed)
+	}
+
 	//// ----- Managing the reference counter ----
 
 	/// Increment the number of time a block is used, putting it to resynchronization if it is
@@ -294,20 +574,27 @@ impl BlockManager {
 
 	/// Read block from disk, verifying it's integrity
 	async fn read_block(&self, hash: &Hash) -> Result<BlockRpc, Error> {
-		let path = self.block_path(hash);
-
-		let mut f = match fs::File::open(&path).await {
-			Ok(f) => f,
-			Err(e) => {
+		let (path, compressed) = match self.locate_block(hash).await {
+			Some(p) => p,
+			None => {
 				// Not found but maybe we should have had it ??
 				self.put_to_resync(hash, Duration::from_millis(0))?;
-				return Err(Into::into(e));
+				return Err(Error::Message(format!("block {:?} not found on disk", hash)));
 			}
 		};
-		let mut data = vec![];
-		f.read_to_end(&mut data).await?;
+
+		let mut f = fs::File::open(&path).await?;
+		let mut file_data = vec![];
+		f.read_to_end(&mut file_data).await?;
 		drop(f);
 
+		let data = if compressed {
+			decode_all(&file_data[..])
+				.map_err(|e| Error::Message(format!("could not decompress block {:?}: {}", hash, e)))?
+		} else {
+			file_data
+		};
+
 		if blake2sum(&data[..]) != *hash {
 			self.mutation_lock
 				.lock()
@@ -320,6 +607,16 @@ impl BlockManager {
 		Ok(BlockRpc::PutBlock(PutBlockMessage { hash: *hash, data }))
 	}
 
+	/// Rewrite a legacy uncompressed block to its compressed form, as part of a scrub pass.
+	/// No-op if compression is disabled, the block is missing, or it is already compressed.
+	async fn migrate_block_to_compressed(&self, hash: &Hash) -> Result<(), Error> {
+		self.mutation_lock
+			.lock()
+			.await
+			.migrate_block_to_compressed(hash, self)
+			.await
+	}
+
 	/// Check if this node should have a block, but don't actually have it
 	async fn need_block(&self, hash: &Hash) -> Result<bool, Error> {
 		let BlockStatus { exists, needed } = self
@@ -331,19 +628,240 @@ impl BlockManager {
 		Ok(needed && !exists)
 	}
 
-	/// Utility: gives the path of the directory in which a block should be found
+	/// Check whether this node actually has `hash` stored on disk, regardless of whether it
+	/// still needs it (see `BlockRpc::HaveBlockQuery`)
+	async fn have_block(&self, hash: &Hash) -> Result<bool, Error> {
+		Ok(self.locate_block(hash).await.is_some())
+	}
+
+	/// Serve one chunk of a streamed `GetBlock`, decompressing on the fly if necessary.
+	///
+	/// Note: for compressed blocks, this re-opens and re-decompresses the file from the start
+	/// for every chunk requested. This still never holds more than one chunk in memory, unlike
+	/// the whole-block buffering this streaming path replaces, at the cost of some redundant
+	/// CPU work across chunks of the same block.
+	///
+	/// Like `read_block`, the plaintext is checked against `req.hash`; unlike `read_block`,
+	/// which can verify the whole block before returning any of it, chunks here are served as
+	/// they are read, so verification has to happen incrementally: a running hash of every
+	/// chunk served so far is kept per requester in `stream_reads`, and the final chunk is
+	/// withheld and the block quarantined (same path as `read_block`) if the completed digest
+	/// doesn't match.
+	async fn handle_get_block_stream_chunk(
+		&self,
+		req: &GetBlockStreamChunkRequest,
+		from: NodeID,
+	) -> Result<BlockRpc, Error> {
+		let (path, compressed) = match self.locate_block(&req.hash).await {
+			Some(p) => p,
+			None => {
+				self.put_to_resync(&req.hash, Duration::from_millis(0))?;
+				return Err(Error::Message(format!(
+					"block {:?} not found on disk",
+					req.hash
+				)));
+			}
+		};
+
+		let f = fs::File::open(&path).await?;
+		let mut reader: Pin<Box<dyn AsyncRead + Send>> = if compressed {
+			Box::pin(ZstdDecoder::new(BufReader::new(f)))
+		} else {
+			Box::pin(f)
+		};
+
+		let mut skip_buf = vec![0u8; STREAM_CHUNK_SIZE];
+		let mut skipped = 0u64;
+		while skipped < req.offset {
+			let want = std::cmp::min(skip_buf.len() as u64, req.offset - skipped) as usize;
+			let n = reader.read(&mut skip_buf[..want]).await?;
+			if n == 0 {
+				break;
+			}
+			skipped += n as u64;
+		}
+
+		let mut data = vec![0u8; STREAM_CHUNK_SIZE];
+		let filled = read_up_to(&mut reader, &mut data).await?;
+		data.truncate(filled);
+
+		let mut probe = [0u8; 1];
+		let is_last = reader.read(&mut probe).await? == 0;
+
+		let key = (from, req.hash, req.request_id);
+		let mut reads = self.stream_reads.lock().await;
+		let state = reads.entry(key).or_insert_with(|| StreamReadState {
+			hasher: Blake2b512::new(),
+			last_activity: now_msec(),
+		});
+		state.hasher.update(&data);
+		state.last_activity = now_msec();
+
+		if !is_last {
+			drop(reads);
+			return Ok(BlockRpc::PutBlockStreamChunk(BlockStreamChunk {
+				hash: req.hash,
+				offset: req.offset,
+				data,
+				is_last,
+			}));
+		}
+
+		let state = reads.remove(&key).unwrap();
+		drop(reads);
+
+		let computed = finalize_stream_hash(state.hasher);
+
+		if computed != req.hash {
+			self.mutation_lock
+				.lock()
+				.await
+				.move_block_to_corrupted(&req.hash, self)
+				.await?;
+			return Err(Error::CorruptData(req.hash));
+		}
+
+		Ok(BlockRpc::PutBlockStreamChunk(BlockStreamChunk {
+			hash: req.hash,
+			offset: req.offset,
+			data,
+			is_last,
+		}))
+	}
+
+	/// Receive one chunk of a streamed `PutBlock`, feeding it into a running integrity check
+	/// and only committing the block to its final path once the last chunk's digest matches.
+	async fn handle_put_block_stream_chunk(
+		&self,
+		chunk: &BlockStreamChunk,
+		from: NodeID,
+	) -> Result<BlockRpc, Error> {
+		let key = (from, chunk.hash);
+
+		let mut writes = self.stream_writes.lock().await;
+		if !writes.contains_key(&key) {
+			let dir = self.block_dir(&chunk.hash);
+			fs::create_dir_all(&dir).await?;
+			// Two different senders can stream the same block hash to us at the same time;
+			// a tmp path derived from the hash alone would let them clobber each other's
+			// bytes on disk even though each stream's own hasher would still validate, since
+			// it only ever sees the chunks that stream itself wrote. A per-call id keeps
+			// every in-progress stream on its own file.
+			let stream_id = self.next_stream_write_id.fetch_add(1, Ordering::Relaxed);
+			let mut tmp_path = dir;
+			tmp_path.push(format!(
+				"{}.{}.stream-tmp",
+				hex::encode(chunk.hash.as_ref()),
+				stream_id
+			));
+			let file = fs::File::create(&tmp_path).await?;
+			writes.insert(
+				key,
+				StreamWriteState {
+					tmp_path,
+					file,
+					hasher: Blake2b512::new(),
+					offset: 0,
+					last_activity: now_msec(),
+				},
+			);
+		}
+
+		let state = writes.get_mut(&key).unwrap();
+		if chunk.offset != state.offset {
+			// Don't leave a write that can never complete (the sender is out of sync with
+			// us and will never send the offset we're expecting again) sitting in the map:
+			// drop its tmp file and entry now rather than waiting for it to be reaped by
+			// `cleanup_stale_stream_writes`.
+			let state = writes.remove(&key).unwrap();
+			drop(writes);
+			drop(state.file);
+			let _ = fs::remove_file(&state.tmp_path).await;
+			return Err(Error::Message(format!(
+				"out-of-order streamed chunk for block {:?}: expected offset {}, got {}",
+				chunk.hash, state.offset, chunk.offset
+			)));
+		}
+		state.file.write_all(&chunk.data).await?;
+		state.hasher.update(&chunk.data);
+		state.offset += chunk.data.len() as u64;
+		state.last_activity = now_msec();
+
+		if !chunk.is_last {
+			return Ok(BlockRpc::Ok);
+		}
+
+		let mut state = writes.remove(&key).unwrap();
+		drop(writes);
+
+		state.file.sync_all().await?;
+		drop(state.file);
+
+		let computed = finalize_stream_hash(state.hasher);
+
+		if computed != chunk.hash {
+			warn!(
+				"Streamed block {:?} failed integrity check, discarding",
+				chunk.hash
+			);
+			let _ = fs::remove_file(&state.tmp_path).await;
+			self.put_to_resync(&chunk.hash, Duration::from_millis(0))?;
+			return Err(Error::CorruptData(chunk.hash));
+		}
+
+		self.mutation_lock
+			.lock()
+			.await
+			.commit_streamed_block(&chunk.hash, &state.tmp_path, self)
+			.await?;
+
+		Ok(BlockRpc::Ok)
+	}
+
+	/// Utility: pick which configured data directory a new block should be written to,
+	/// weighted by each directory's configured capacity so that blocks spread across disks
+	/// roughly proportionally to their size. The choice is a deterministic function of the
+	/// hash, so repeated calls for the same block agree on the same directory.
+	fn select_data_dir(&self, hash: &Hash) -> &Path {
+		// The capacities are asserted to sum to > 0 once, in `BlockManager::new`, so that a
+		// misconfigured node fails fast at startup instead of panicking on the first block
+		// read/write.
+		let capacities: Vec<u64> = self.data_dirs.iter().map(|d| d.capacity).collect();
+		let index = weighted_dir_index(hash, &capacities);
+		&self.data_dirs[index].path
+	}
+
+	/// Utility: gives the path of the directory in which a new block should be written
 	fn block_dir(&self, hash: &Hash) -> PathBuf {
-		let mut path = self.data_dir.clone();
-		path.push(hex::encode(&hash.as_slice()[0..1]));
-		path.push(hex::encode(&hash.as_slice()[1..2]));
-		path
+		block_dir_in(self.select_data_dir(hash), hash)
 	}
 
-	/// Utility: give the full path where a block should be found
+	/// Utility: give the full path where a new block should be written, assuming it is stored
+	/// uncompressed (legacy on-disk format)
 	fn block_path(&self, hash: &Hash) -> PathBuf {
-		let mut path = self.block_dir(hash);
-		path.push(hex::encode(hash.as_ref()));
-		path
+		block_path_in(self.select_data_dir(hash), hash)
+	}
+
+	/// Utility: locate a block on disk, probing every configured data directory (a block may
+	/// have been written to a directory that isn't the one `select_data_dir` would currently
+	/// pick, e.g. if a directory was since added), and looking for both the compressed and
+	/// the legacy uncompressed forms. Returns the path to the file found, along with whether
+	/// it is compressed.
+	async fn locate_block(&self, hash: &Hash) -> Option<(PathBuf, bool)> {
+		for dir in self.data_dirs.iter() {
+			let mut compressed_path = block_path_in(&dir.path, hash);
+			compressed_path.set_extension(BLOCK_COMPRESSED_EXT);
+			if fs::metadata(&compressed_path).await.is_ok() {
+				return Some((compressed_path, true));
+			}
+
+			let plain_path = block_path_in(&dir.path, hash);
+			if fs::metadata(&plain_path).await.is_ok() {
+				return Some((plain_path, false));
+			}
+		}
+
+		None
 	}
 
 	// ---- Resync loop ----
@@ -360,6 +878,62 @@ impl BlockManager {
 				});
 			});
 		}
+
+		// Runs on its own timer instead of piggy-backing on resync_loop, which can otherwise
+		// sleep far longer than STREAM_WRITE_TIMEOUT/STREAM_READ_TIMEOUT while the resync
+		// queue is idle or waiting out a scheduled retry.
+		self.system.background.spawn_worker(
+			"block stream cleanup worker".into(),
+			move |must_exit| self.stream_cleanup_loop(must_exit),
+		);
+	}
+
+	async fn stream_cleanup_loop(self: Arc<Self>, mut must_exit: watch::Receiver<bool>) {
+		while !*must_exit.borrow() {
+			self.cleanup_stale_stream_writes().await;
+			self.cleanup_stale_stream_reads().await;
+
+			let delay = tokio::time::sleep(STREAM_CLEANUP_INTERVAL);
+			select! {
+				_ = delay.fuse() => {},
+				_ = must_exit.changed().fuse() => {},
+			}
+		}
+	}
+
+	/// Reap streamed `PutBlock` writes that haven't received a chunk in over
+	/// `STREAM_WRITE_TIMEOUT`, e.g. because the sender crashed or disconnected mid-transfer.
+	/// Without this, an abandoned write's entry and open tmp-file descriptor would stay
+	/// around forever, and the first chunk of a retry with the same `(from, hash)` key would
+	/// be rejected as out-of-order against the stale offset.
+	async fn cleanup_stale_stream_writes(&self) {
+		let now = now_msec();
+		let mut writes = self.stream_writes.lock().await;
+		let stale_keys: Vec<_> = writes
+			.iter()
+			.filter(|(_, state)| is_stream_stale(now, state.last_activity, STREAM_WRITE_TIMEOUT))
+			.map(|(key, _)| *key)
+			.collect();
+
+		for key in stale_keys {
+			if let Some(state) = writes.remove(&key) {
+				warn!(
+					"Discarding streamed PutBlock for {:?} from {:?}: no chunk received in over {:?}",
+					key.1, key.0, STREAM_WRITE_TIMEOUT
+				);
+				drop(state.file);
+				let _ = fs::remove_file(&state.tmp_path).await;
+			}
+		}
+	}
+
+	/// Reap streamed `GetBlock` reads that haven't had a chunk requested in over
+	/// `STREAM_READ_TIMEOUT`, e.g. because the requester disconnected mid-transfer. Without
+	/// this, an abandoned read's entry in `stream_reads` would stay around forever.
+	async fn cleanup_stale_stream_reads(&self) {
+		let now = now_msec();
+		let mut reads = self.stream_reads.lock().await;
+		reads.retain(|_, state| !is_stream_stale(now, state.last_activity, STREAM_READ_TIMEOUT));
 	}
 
 	fn put_to_resync(&self, hash: &Hash, delay: Duration) -> Result<(), Error> {
@@ -372,6 +946,39 @@ impl BlockManager {
 		Ok(())
 	}
 
+	/// Record a resync failure for `hash` and return its new error count together with the
+	/// backoff delay computed for it, so the caller can schedule the actual retry
+	/// (`put_to_resync`) with the exact same delay that was persisted here for operator
+	/// reporting -- `resync_backoff_delay` draws a fresh random jitter on every call, so
+	/// computing it twice would make the reported `next_try` never match the real retry time.
+	fn record_resync_error(&self, hash: &Hash, error: &Error) -> Result<(u64, Duration), Error> {
+		let now = now_msec();
+		let previous = self
+			.resync_errors
+			.get(hash.as_ref())?
+			.map(|b| rmp_serde::decode::from_read_ref::<_, ResyncErrorInfo>(b.as_ref()))
+			.transpose()?;
+		let error_count = previous.map(|i| i.error_count + 1).unwrap_or(1);
+		let delay = resync_backoff_delay(error_count);
+
+		let info = ResyncErrorInfo {
+			error_count,
+			last_error: error.to_string(),
+			last_try: now,
+			next_try: now + delay.as_millis() as u64,
+		};
+		let bytes = rmp_serde::encode::to_vec_named(&info)?;
+		self.resync_errors.insert(hash.as_ref(), bytes)?;
+
+		Ok((error_count, delay))
+	}
+
+	/// Clear the resync error history for `hash` after a successful resync
+	fn clear_resync_error(&self, hash: &Hash) -> Result<(), Error> {
+		self.resync_errors.remove(hash.as_ref())?;
+		Ok(())
+	}
+
 	async fn resync_loop(self: Arc<Self>, mut must_exit: watch::Receiver<bool>) {
 		let mut tranquilizer = Tranquilizer::new(30);
 
@@ -406,8 +1013,14 @@ impl BlockManager {
 				let hash = Hash::try_from(&hash_bytes[..]).unwrap();
 				let res = self.resync_block(&hash).await;
 				if let Err(e) = &res {
-					warn!("Error when resyncing {:?}: {}", hash, e);
-					self.put_to_resync(&hash, RESYNC_RETRY_TIMEOUT)?;
+					let (error_count, delay) = self.record_resync_error(&hash, e)?;
+					warn!(
+						"Error when resyncing {:?} (attempt {}): {} (retrying in {:?})",
+						hash, error_count, e, delay
+					);
+					self.put_to_resync(&hash, delay)?;
+				} else {
+					self.clear_resync_error(&hash)?;
 				}
 				Ok(true)
 			} else {
@@ -500,6 +1113,29 @@ impl BlockManager {
 							.with_timeout(BLOCK_RW_TIMEOUT),
 					)
 					.await?;
+
+				// try_call_many returning Ok only means each PutBlock call succeeded when it
+				// was made, not that the nodes still hold the block now. Re-check explicitly,
+				// on every node we count, before deleting our only copy.
+				let confirmed = self.count_confirmed_holders(hash, &need_nodes).await?;
+				let not_needing: Vec<NodeID> = who
+					.iter()
+					.filter(|node| !need_nodes.contains(node))
+					.cloned()
+					.collect();
+				let already_holding = self.count_confirmed_holders(hash, &not_needing).await?;
+				let replication_factor = self.replication.replication_factor();
+				if !enough_confirmed_replicas(confirmed, already_holding, replication_factor) {
+					self.offloads_deferred.fetch_add(1, Ordering::Relaxed);
+					info!(
+						"Deferring deletion of block {:?}: only {} of {} replicas confirmed after offload",
+						hash,
+						confirmed + already_holding,
+						replication_factor
+					);
+					self.put_to_resync(hash, RESYNC_RETRY_TIMEOUT)?;
+					return Ok(());
+				}
 			}
 			info!(
 				"Deleting block {:?}, offload finished ({} / {})",
@@ -526,9 +1162,35 @@ impl BlockManager {
 		Ok(())
 	}
 
+	/// Re-query a set of nodes after sending them a block, to count how many now actually
+	/// have it stored on disk. Used to make sure enough replicas actually persisted the block
+	/// before an offloading node deletes its local copy. Uses `HaveBlockQuery` rather than
+	/// `NeedBlockQuery`: a `NeedBlockReply(false)` only means the node doesn't currently need
+	/// the block, which is also true for a node that lost its need for it in the meantime
+	/// (e.g. a concurrent delete) without ever having received the data -- trusting that to
+	/// decide whether it's safe to delete our only copy would risk losing the block entirely.
+	async fn count_confirmed_holders(&self, hash: &Hash, nodes: &[NodeID]) -> Result<usize, Error> {
+		let msg = Arc::new(BlockRpc::HaveBlockQuery(*hash));
+		let futs = nodes.iter().map(|to| {
+			self.system.rpc.call_arc(
+				&self.endpoint,
+				*to,
+				msg.clone(),
+				RequestStrategy::with_priority(PRIO_BACKGROUND).with_timeout(NEED_BLOCK_QUERY_TIMEOUT),
+			)
+		});
+		let resps = join_all(futs).await;
+
+		let confirmed = resps
+			.into_iter()
+			.filter(|r| matches!(r, Ok(BlockRpc::HaveBlockReply(true))))
+			.count();
+		Ok(confirmed)
+	}
+
 	async fn for_each_file<F, Fut, State>(
 		&self,
-		state: State,
+		mut state: State,
 		mut f: F,
 		must_exit: &watch::Receiver<bool>,
 	) -> Result<(), Error>
@@ -537,9 +1199,15 @@ impl BlockManager {
 		Fut: Future<Output = Result<State, Error>> + Send,
 		State: Send,
 	{
-		self.for_each_file_rec(&self.data_dir, state, &mut f, must_exit)
-			.await
-			.map(|_| ())
+		for dir in self.data_dirs.iter() {
+			if *must_exit.borrow() {
+				break;
+			}
+			state = self
+				.for_each_file_rec(&dir.path, state, &mut f, must_exit)
+				.await?;
+		}
+		Ok(())
 	}
 
 	fn for_each_file_rec<'a, F, Fut, State>(
@@ -573,15 +1241,8 @@ impl BlockManager {
 					state = self
 						.for_each_file_rec(&data_dir_ent.path(), state, f, must_exit)
 						.await?;
-				} else if name.len() == 64 {
-					let hash_bytes = if let Ok(h) = hex::decode(&name) {
-						h
-					} else {
-						continue;
-					};
-					let mut hash = [0u8; 32];
-					hash.copy_from_slice(&hash_bytes[..]);
-					state = f(state, hash.into()).await?;
+				} else if let Some(hash) = block_hash_from_filename(&name) {
+					state = f(state, hash).await?;
 				}
 			}
 			Ok(state)
@@ -595,12 +1256,19 @@ impl EndpointHandler<BlockRpc> for BlockManager {
 	async fn handle(
 		self: &Arc<Self>,
 		message: &BlockRpc,
-		_from: NodeID,
+		from: NodeID,
 	) -> Result<BlockRpc, Error> {
 		match message {
 			BlockRpc::PutBlock(m) => self.write_block(&m.hash, &m.data).await,
 			BlockRpc::GetBlock(h) => self.read_block(h).await,
 			BlockRpc::NeedBlockQuery(h) => self.need_block(h).await.map(BlockRpc::NeedBlockReply),
+			BlockRpc::HaveBlockQuery(h) => self.have_block(h).await.map(BlockRpc::HaveBlockReply),
+			BlockRpc::GetBlockStreamChunk(req) => {
+				self.handle_get_block_stream_chunk(req, from).await
+			}
+			BlockRpc::PutBlockStreamChunk(chunk) => {
+				self.handle_put_block_stream_chunk(chunk, from).await
+			}
 			_ => Err(Error::BadRpc("Unexpected RPC message".to_string())),
 		}
 	}
@@ -611,15 +1279,56 @@ struct BlockStatus {
 	needed: bool,
 }
 
+/// Persisted bookkeeping for a block that has failed to resync at least once
+#[derive(Serialize, Deserialize)]
+struct ResyncErrorInfo {
+	error_count: u64,
+	last_error: String,
+	last_try: u64,
+	next_try: u64,
+}
+
+/// Information about a block that has repeatedly failed to resync, as surfaced to operators so
+/// they can tell a block is stuck rather than merely slow to catch up
+pub struct ResyncErrorDetail {
+	pub hash: Hash,
+	pub error_count: u64,
+	pub last_error: String,
+	pub next_try: u64,
+}
+
+/// Bookkeeping for a block being received in chunks: the data received so far is written to
+/// `tmp_path` as it arrives and fed into `hasher`, so the whole block is never held in memory
+/// at once. The file is only moved to its final location once the last chunk arrives and
+/// `hasher`'s digest matches the requested hash.
+struct StreamWriteState {
+	tmp_path: PathBuf,
+	file: fs::File,
+	hasher: Blake2b512,
+	offset: u64,
+	/// Time (`now_msec`) the last chunk for this stream was received, so an abandoned
+	/// transfer (sender crashed or disconnected mid-stream) can be evicted by
+	/// `cleanup_stale_stream_writes` instead of leaking its tmp file and entry forever.
+	last_activity: u64,
+}
+
+/// Bookkeeping for a streamed `GetBlock` in progress: `hasher` accumulates the chunks served
+/// so far so the completed digest can be checked against the requested hash once the last
+/// chunk is reached. `last_activity` lets `cleanup_stale_stream_reads` evict a stream whose
+/// requester went away before requesting its last chunk, the read-side equivalent of
+/// `StreamWriteState::last_activity`.
+struct StreamReadState {
+	hasher: Blake2b512,
+	last_activity: u64,
+}
+
 impl BlockManagerLocked {
 	async fn check_block_status(
 		&self,
 		hash: &Hash,
 		mgr: &BlockManager,
 	) -> Result<BlockStatus, Error> {
-		let path = mgr.block_path(hash);
-
-		let exists = fs::metadata(&path).await.is_ok();
+		let exists = mgr.locate_block(hash).await.is_some();
 		let needed = mgr.get_block_rc(hash)? > 0;
 
 		Ok(BlockStatus { exists, needed })
@@ -631,18 +1340,29 @@ impl BlockManagerLocked {
 		data: &[u8],
 		mgr: &BlockManager,
 	) -> Result<BlockRpc, Error> {
-		let mut path = mgr.block_dir(hash);
-		fs::create_dir_all(&path).await?;
+		let dir = mgr.block_dir(hash);
+		fs::create_dir_all(&dir).await?;
 
-		path.push(hex::encode(hash));
-		if fs::metadata(&path).await.is_ok() {
+		if mgr.locate_block(hash).await.is_some() {
 			return Ok(BlockRpc::Ok);
 		}
 
+		let (to_write, path) = match mgr.compression_level {
+			Some(level) => {
+				let compressed = encode_all(data, level).map_err(|e| {
+					Error::Message(format!("could not compress block {:?}: {}", hash, e))
+				})?;
+				let mut path = mgr.block_path(hash);
+				path.set_extension(BLOCK_COMPRESSED_EXT);
+				(compressed, path)
+			}
+			None => (data.to_vec(), mgr.block_path(hash)),
+		};
+
 		let mut path2 = path.clone();
 		path2.set_extension("tmp");
 		let mut f = fs::File::create(&path2).await?;
-		f.write_all(data).await?;
+		f.write_all(&to_write).await?;
 		drop(f);
 
 		fs::rename(path2, path).await?;
@@ -655,7 +1375,9 @@ impl BlockManagerLocked {
 			"Block {:?} is corrupted. Renaming to .corrupted and resyncing.",
 			hash
 		);
-		let path = mgr.block_path(hash);
+		let (path, _compressed) = mgr.locate_block(hash).await.ok_or_else(|| {
+			Error::Message(format!("block {:?} vanished before it could be quarantined", hash))
+		})?;
 		let mut path2 = path.clone();
 		path2.set_extension("corrupted");
 		fs::rename(path, path2).await?;
@@ -667,11 +1389,222 @@ impl BlockManagerLocked {
 		let BlockStatus { exists, needed } = self.check_block_status(hash, mgr).await?;
 
 		if exists && !needed {
-			let path = mgr.block_path(hash);
-			fs::remove_file(path).await?;
+			if let Some((path, _compressed)) = mgr.locate_block(hash).await {
+				fs::remove_file(path).await?;
+			}
 		}
 		Ok(())
 	}
+
+	/// Rewrite a legacy uncompressed block to its compressed form. No-op if compression is
+	/// disabled, the block is missing, or it is already compressed.
+	async fn migrate_block_to_compressed(
+		&self,
+		hash: &Hash,
+		mgr: &BlockManager,
+	) -> Result<(), Error> {
+		let level = match mgr.compression_level {
+			Some(level) => level,
+			None => return Ok(()),
+		};
+
+		let (path, compressed) = match mgr.locate_block(hash).await {
+			Some(p) => p,
+			None => return Ok(()),
+		};
+		if compressed {
+			return Ok(());
+		}
+
+		let data = fs::read(&path).await?;
+		if blake2sum(&data[..]) != *hash {
+			// Don't migrate corrupted data; the regular read path will quarantine it.
+			return Ok(());
+		}
+
+		let compressed_data = encode_all(&data[..], level)
+			.map_err(|e| Error::Message(format!("could not compress block {:?}: {}", hash, e)))?;
+
+		let mut new_path = path.clone();
+		new_path.set_extension(BLOCK_COMPRESSED_EXT);
+		let mut tmp_path = new_path.clone();
+		tmp_path.set_extension("tmp");
+		fs::write(&tmp_path, &compressed_data).await?;
+		fs::rename(&tmp_path, &new_path).await?;
+		fs::remove_file(&path).await?;
+
+		Ok(())
+	}
+
+	/// Commit a block that was received in a validated stream (its integrity has already been
+	/// checked by the caller) from its plaintext temporary file into its final on-disk form,
+	/// compressing it on the way if compression is enabled. The copy/compression itself is
+	/// streamed disk-to-disk, so it does not hold the whole block in memory either.
+	async fn commit_streamed_block(
+		&self,
+		hash: &Hash,
+		tmp_path: &Path,
+		mgr: &BlockManager,
+	) -> Result<(), Error> {
+		if mgr.locate_block(hash).await.is_some() {
+			let _ = fs::remove_file(tmp_path).await;
+			return Ok(());
+		}
+
+		match mgr.compression_level {
+			Some(level) => {
+				let mut dest_path = mgr.block_path(hash);
+				dest_path.set_extension(BLOCK_COMPRESSED_EXT);
+				let mut dest_tmp = dest_path.clone();
+				dest_tmp.set_extension("tmp");
+
+				let src = fs::File::open(tmp_path).await?;
+				let dest = fs::File::create(&dest_tmp).await?;
+				let mut reader = BufReader::new(src);
+				let mut encoder = ZstdEncoder::with_quality(dest, Level::Precise(level));
+				tokio::io::copy(&mut reader, &mut encoder).await?;
+				encoder.shutdown().await?;
+
+				fs::rename(&dest_tmp, &dest_path).await?;
+				fs::remove_file(tmp_path).await?;
+			}
+			None => {
+				let dest_path = mgr.block_path(hash);
+				fs::rename(tmp_path, &dest_path).await?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Utility: gives the path of the directory in which a block would be found within a given
+/// data directory
+fn block_dir_in(root: &Path, hash: &Hash) -> PathBuf {
+	let mut path = root.to_path_buf();
+	path.push(hex::encode(&hash.as_slice()[0..1]));
+	path.push(hex::encode(&hash.as_slice()[1..2]));
+	path
+}
+
+/// Utility: give the full path where a block would be found within a given data directory,
+/// assuming it is stored uncompressed (legacy on-disk format)
+fn block_path_in(root: &Path, hash: &Hash) -> PathBuf {
+	let mut path = block_dir_in(root, hash);
+	path.push(hex::encode(hash.as_ref()));
+	path
+}
+
+/// Fill `buf` as much as possible by reading repeatedly from `reader` until either `buf` is
+/// full or end-of-stream is reached. Returns the number of bytes actually read.
+async fn read_up_to(
+	reader: &mut (impl AsyncRead + Unpin + Send),
+	buf: &mut [u8],
+) -> Result<usize, Error> {
+	let mut filled = 0;
+	while filled < buf.len() {
+		let n = reader.read(&mut buf[filled..]).await?;
+		if n == 0 {
+			break;
+		}
+		filled += n;
+	}
+	Ok(filled)
+}
+
+/// Parse the hash encoded in a block's on-disk file name, recognizing both the legacy
+/// uncompressed form (bare hex hash) and the compressed form (hex hash with a `.zst` suffix).
+/// Returns `None` for anything else (e.g. `.tmp` or `.corrupted` files).
+fn block_hash_from_filename(name: &str) -> Option<Hash> {
+	let hex_part = if name.len() == 64 {
+		name
+	} else if let Some(prefix) = name.strip_suffix(&format!(".{}", BLOCK_COMPRESSED_EXT)) {
+		if prefix.len() == 64 {
+			prefix
+		} else {
+			return None;
+		}
+	} else {
+		return None;
+	};
+
+	let hash_bytes = hex::decode(hex_part).ok()?;
+	let mut hash = [0u8; 32];
+	hash.copy_from_slice(&hash_bytes[..]);
+	Some(hash.into())
+}
+
+/// Compute the delay before the next resync attempt for a block that has failed `error_count`
+/// times in a row: doubles `RESYNC_RETRY_TIMEOUT` for each failure, up to
+/// `RESYNC_RETRY_BACKOFF_CAP` doublings, plus a random jitter so that many blocks that started
+/// failing at the same time don't all retry in lockstep.
+fn resync_backoff_delay(error_count: u64) -> Duration {
+	let exponent = u32::try_from(error_count.saturating_sub(1))
+		.unwrap_or(u32::MAX)
+		.min(RESYNC_RETRY_BACKOFF_CAP);
+	let base_ms = RESYNC_RETRY_TIMEOUT.as_millis() as u64;
+	let delay_ms = base_ms.saturating_mul(1u64 << exponent);
+	let jitter_ms = rand::thread_rng().gen_range(0..=(delay_ms / 4).max(1));
+	Duration::from_millis(delay_ms + jitter_ms)
+}
+
+#[cfg(test)]
+mod test_block_hash_from_filename {
+	use super::*;
+
+	#[test]
+	fn parses_legacy_uncompressed_filename() {
+		let hash = Hash::from([0xab; 32]);
+		let name = hex::encode(hash.as_ref());
+		assert_eq!(block_hash_from_filename(&name), Some(hash));
+	}
+
+	#[test]
+	fn parses_compressed_filename() {
+		let hash = Hash::from([0xcd; 32]);
+		let name = format!("{}.{}", hex::encode(hash.as_ref()), BLOCK_COMPRESSED_EXT);
+		assert_eq!(block_hash_from_filename(&name), Some(hash));
+	}
+
+	#[test]
+	fn rejects_non_block_filenames() {
+		assert_eq!(block_hash_from_filename("somefile.tmp"), None);
+		assert_eq!(block_hash_from_filename("somefile.corrupted"), None);
+		assert_eq!(block_hash_from_filename("not-hex-and-not-64-chars"), None);
+	}
+}
+
+#[cfg(test)]
+mod test_resync_backoff_delay {
+	use super::*;
+
+	#[test]
+	fn grows_with_error_count_up_to_the_cap() {
+		let first = resync_backoff_delay(1).as_millis();
+		let second = resync_backoff_delay(2).as_millis();
+		// Account for jitter (up to 1/4 of the un-jittered delay) by comparing against the
+		// smallest possible value of the next delay.
+		let second_min = RESYNC_RETRY_TIMEOUT.as_millis() * 2;
+		assert!(first < second_min);
+		assert!(second as u128 >= second_min);
+	}
+
+	#[test]
+	fn stops_growing_past_the_backoff_cap() {
+		let at_cap = resync_backoff_delay(RESYNC_RETRY_BACKOFF_CAP as u64 + 1).as_millis();
+		let past_cap = resync_backoff_delay(RESYNC_RETRY_BACKOFF_CAP as u64 + 10).as_millis();
+		let base_at_cap = RESYNC_RETRY_TIMEOUT.as_millis() << RESYNC_RETRY_BACKOFF_CAP;
+		// Both should be within one jitter factor (1/4) of the same capped base delay.
+		assert!(at_cap as u128 >= base_at_cap);
+		assert!(past_cap as u128 >= base_at_cap);
+		assert!((at_cap as u128) < base_at_cap + base_at_cap / 4 + 1);
+		assert!((past_cap as u128) < base_at_cap + base_at_cap / 4 + 1);
+	}
+
+	#[test]
+	fn never_panics_on_huge_error_counts() {
+		let _ = resync_backoff_delay(u64::MAX);
+	}
 }
 
 fn u64_from_be_bytes<T: AsRef<[u8]>>(bytes: T) -> u64 {
@@ -680,3 +1613,165 @@ fn u64_from_be_bytes<T: AsRef<[u8]>>(bytes: T) -> u64 {
 	x8.copy_from_slice(bytes.as_ref());
 	u64::from_be_bytes(x8)
 }
+
+/// Pure capacity-weighted choice used by `BlockManager::select_data_dir`: given the
+/// configured directories' capacities (in the same order as `data_dirs`), returns the index
+/// of the one a block with this hash should be placed in. Kept separate from
+/// `BlockManager::select_data_dir` so the weighting math can be unit-tested without
+/// constructing a full `BlockManager`. Callers are expected to have already checked that the
+/// capacities sum to more than zero (`BlockManager::new` does this once at startup).
+fn weighted_dir_index(hash: &Hash, capacities: &[u64]) -> usize {
+	let total_capacity: u64 = capacities.iter().sum();
+	let point = u64_from_be_bytes(&hash.as_slice()[0..8]) % total_capacity;
+	let mut acc = 0u64;
+	for (i, capacity) in capacities.iter().enumerate() {
+		acc += capacity;
+		if point < acc {
+			return i;
+		}
+	}
+	// Not reached when the capacities sum to more than zero (`point` is below the total,
+	// which is `acc` after the last directory); fall back to the last directory
+	capacities.len() - 1
+}
+
+#[cfg(test)]
+mod test_weighted_dir_index {
+	use super::*;
+
+	fn hash_with_first_bytes(bytes: [u8; 8]) -> Hash {
+		let mut h = [0u8; 32];
+		h[0..8].copy_from_slice(&bytes);
+		h.into()
+	}
+
+	#[test]
+	fn single_dir_always_chosen() {
+		let hash = hash_with_first_bytes([42; 8]);
+		assert_eq!(weighted_dir_index(&hash, &[100]), 0);
+	}
+
+	#[test]
+	fn picks_dir_matching_the_capacity_bucket() {
+		// capacities [10, 20, 30] -> buckets [0..10), [10..30), [30..60)
+		let capacities = [10u64, 20, 30];
+
+		let low = hash_with_first_bytes(0u64.to_be_bytes());
+		assert_eq!(weighted_dir_index(&low, &capacities), 0);
+
+		let mid = hash_with_first_bytes(15u64.to_be_bytes());
+		assert_eq!(weighted_dir_index(&mid, &capacities), 1);
+
+		let high = hash_with_first_bytes(59u64.to_be_bytes());
+		assert_eq!(weighted_dir_index(&high, &capacities), 2);
+	}
+
+	#[test]
+	fn is_deterministic_for_the_same_hash() {
+		let hash = hash_with_first_bytes([7; 8]);
+		let capacities = [5u64, 5, 5];
+		let first = weighted_dir_index(&hash, &capacities);
+		let second = weighted_dir_index(&hash, &capacities);
+		assert_eq!(first, second);
+	}
+}
+
+/// Pure decision used by `resync_block` after an offload: whether enough replicas are now
+/// confirmed to hold `hash` that it's safe to delete the offloading node's own copy. Kept
+/// separate so this irreversible-deletion criterion can be unit-tested without a running
+/// `BlockManager`/RPC stack.
+fn enough_confirmed_replicas(confirmed: usize, already_holding: usize, replication_factor: usize) -> bool {
+	confirmed + already_holding >= replication_factor
+}
+
+#[cfg(test)]
+mod test_enough_confirmed_replicas {
+	use super::*;
+
+	#[test]
+	fn deletion_allowed_once_replication_factor_confirmed() {
+		assert!(enough_confirmed_replicas(2, 1, 3));
+		assert!(enough_confirmed_replicas(3, 0, 3));
+	}
+
+	#[test]
+	fn deletion_deferred_when_short_of_replication_factor() {
+		assert!(!enough_confirmed_replicas(1, 1, 3));
+		assert!(!enough_confirmed_replicas(0, 0, 3));
+	}
+
+	#[test]
+	fn boundary_is_inclusive() {
+		assert!(enough_confirmed_replicas(0, 3, 3));
+	}
+}
+
+/// Whether a streamed read or write that last made progress at `last_activity` (a `now_msec`
+/// timestamp) should be considered abandoned and reaped, given `timeout`. Shared by
+/// `cleanup_stale_stream_writes` and `cleanup_stale_stream_reads` so the staleness rule is
+/// unit-tested once rather than duplicated across both.
+fn is_stream_stale(now: u64, last_activity: u64, timeout: Duration) -> bool {
+	now.saturating_sub(last_activity) > timeout.as_millis() as u64
+}
+
+#[cfg(test)]
+mod test_is_stream_stale {
+	use super::*;
+
+	#[test]
+	fn not_stale_within_the_timeout() {
+		assert!(!is_stream_stale(1_000, 900, Duration::from_millis(200)));
+	}
+
+	#[test]
+	fn stale_past_the_timeout() {
+		assert!(is_stream_stale(1_500, 900, Duration::from_millis(200)));
+	}
+
+	#[test]
+	fn clock_going_backwards_is_not_mistaken_for_staleness() {
+		// saturating_sub guards against last_activity > now (e.g. a clock adjustment)
+		assert!(!is_stream_stale(900, 1_000, Duration::from_millis(200)));
+	}
+}
+
+/// Finalize the running hash of a streamed block transfer (either direction) into the `Hash`
+/// to compare against the block's expected hash. Shared by `handle_put_block_stream_chunk` and
+/// `handle_get_block_stream_chunk` so the incremental integrity check used by both is
+/// unit-tested once.
+fn finalize_stream_hash(hasher: Blake2b512) -> Hash {
+	let digest = hasher.finalize();
+	let mut bytes = [0u8; 32];
+	bytes.copy_from_slice(&digest[..32]);
+	bytes.into()
+}
+
+#[cfg(test)]
+mod test_finalize_stream_hash {
+	use super::*;
+
+	#[test]
+	fn matches_hashing_the_whole_block_at_once() {
+		let data = b"some block content, fed to the hasher as two separate chunks";
+
+		let mut hasher = Blake2b512::new();
+		hasher.update(&data[..20]);
+		hasher.update(&data[20..]);
+		let incremental = finalize_stream_hash(hasher);
+
+		assert_eq!(incremental, blake2sum(&data[..]));
+	}
+
+	#[test]
+	fn detects_corrupted_chunks() {
+		let original = b"some block content";
+		let mut corrupted = original.to_vec();
+		corrupted[5] ^= 0xff;
+
+		let mut hasher = Blake2b512::new();
+		hasher.update(&corrupted);
+		let computed = finalize_stream_hash(hasher);
+
+		assert_ne!(computed, blake2sum(&original[..]));
+	}
+}